@@ -0,0 +1,494 @@
+//! Comparison of rendered surfaces, for the test suite.
+
+use std::fmt;
+
+use cairo::{Format, ImageSurface};
+
+use crate::surface_utils::shared_surface::{Pixel, SharedImageSurface, SurfaceType};
+
+/// How two surfaces should be compared to each other.
+#[derive(Clone, Copy, Debug)]
+pub enum CompareMode {
+    /// Compare raw, per-channel pixel values; any nonzero difference counts
+    /// as a changed pixel.
+    Naive,
+
+    /// Perceptual comparison based on the YIQ color space, which ignores
+    /// differences caused by anti-aliasing (e.g. a one-pixel shift along a
+    /// curve's edge).  `threshold` is in `[0, 1]`; higher values tolerate
+    /// larger perceptual differences.
+    AntiAliasing { threshold: f64 },
+}
+
+impl Default for CompareMode {
+    fn default() -> Self {
+        CompareMode::Naive
+    }
+}
+
+/// The result of comparing two surfaces of the same size.
+pub struct Diff {
+    pub surface: SharedImageSurface,
+    pub num_pixels_changed: usize,
+    pub max_diff: u8,
+}
+
+/// The result of comparing two surfaces.
+pub enum BufferDiff {
+    DifferentSizes,
+    Diff(Diff),
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} pixels changed with maximum difference of {}",
+            self.num_pixels_changed, self.max_diff
+        )
+    }
+}
+
+impl fmt::Display for BufferDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BufferDiff::DifferentSizes => write!(f, "the surfaces are of different sizes"),
+            BufferDiff::Diff(diff) => write!(f, "{}", diff),
+        }
+    }
+}
+
+const RED_PIXEL: Pixel = Pixel {
+    r: 255,
+    g: 0,
+    b: 0,
+    a: 255,
+};
+
+const YELLOW_PIXEL: Pixel = Pixel {
+    r: 255,
+    g: 255,
+    b: 0,
+    a: 255,
+};
+
+/// Accumulates per-pixel diff results into a premultiplied `ARgb32` buffer
+/// that can be turned into a `SharedImageSurface` once the comparison is done.
+struct DiffImage {
+    width: i32,
+    height: i32,
+    stride: i32,
+    data: Vec<u8>,
+}
+
+impl DiffImage {
+    fn new(width: i32, height: i32, stride: i32) -> Self {
+        Self {
+            width,
+            height,
+            stride,
+            data: vec![0; (stride as usize) * (height as usize)],
+        }
+    }
+
+    fn set_pixel(&mut self, x: u32, y: u32, pixel: Pixel) {
+        let offset = y as usize * self.stride as usize + x as usize * 4;
+        self.data[offset] = pixel.b;
+        self.data[offset + 1] = pixel.g;
+        self.data[offset + 2] = pixel.r;
+        self.data[offset + 3] = pixel.a;
+    }
+
+    fn into_shared_surface(self) -> Result<SharedImageSurface, cairo::Status> {
+        let mut surface = ImageSurface::create(Format::ARgb32, self.width, self.height)?;
+
+        {
+            let mut surface_data = surface.get_data().map_err(|_| cairo::Status::SurfaceFinished)?;
+            surface_data.copy_from_slice(&self.data);
+        }
+
+        SharedImageSurface::wrap(surface, SurfaceType::SRgb)
+    }
+}
+
+/// Compares two surfaces and returns a `BufferDiff` describing how they differ.
+pub fn compare_surfaces(
+    surf_a: &SharedImageSurface,
+    surf_b: &SharedImageSurface,
+) -> Result<BufferDiff, cairo::Status> {
+    compare_surfaces_with_mode(surf_a, surf_b, CompareMode::default())
+}
+
+/// Like `compare_surfaces`, but lets the caller pick the comparison mode.
+pub fn compare_surfaces_with_mode(
+    surf_a: &SharedImageSurface,
+    surf_b: &SharedImageSurface,
+    mode: CompareMode,
+) -> Result<BufferDiff, cairo::Status> {
+    let width = surf_a.get_width();
+    let height = surf_a.get_height();
+
+    if width != surf_b.get_width() || height != surf_b.get_height() {
+        return Ok(BufferDiff::DifferentSizes);
+    }
+
+    let stride = width * 4;
+    let mut diff_image = DiffImage::new(width, height, stride);
+
+    let (num_pixels_changed, max_diff) = match mode {
+        CompareMode::Naive => naive_diff(surf_a, surf_b, &mut diff_image),
+        CompareMode::AntiAliasing { threshold } => {
+            anti_aliasing_diff(surf_a, surf_b, &mut diff_image, threshold)
+        }
+    };
+
+    let surface = diff_image.into_shared_surface()?;
+
+    Ok(BufferDiff::Diff(Diff {
+        surface,
+        num_pixels_changed,
+        max_diff,
+    }))
+}
+
+fn diff_channel(a: u8, b: u8) -> u8 {
+    (i32::from(a) - i32::from(b)).unsigned_abs() as u8
+}
+
+fn naive_diff(
+    surf_a: &SharedImageSurface,
+    surf_b: &SharedImageSurface,
+    diff_image: &mut DiffImage,
+) -> (usize, u8) {
+    let width = surf_a.get_width() as u32;
+    let height = surf_a.get_height() as u32;
+
+    let mut num_pixels_changed = 0;
+    let mut max_diff = 0;
+
+    for y in 0..height {
+        for x in 0..width {
+            let pixel_a = surf_a.get_pixel(x, y);
+            let pixel_b = surf_b.get_pixel(x, y);
+
+            let this_max = [
+                diff_channel(pixel_a.r, pixel_b.r),
+                diff_channel(pixel_a.g, pixel_b.g),
+                diff_channel(pixel_a.b, pixel_b.b),
+                diff_channel(pixel_a.a, pixel_b.a),
+            ]
+            .iter()
+            .copied()
+            .max()
+            .unwrap();
+
+            if this_max > 0 {
+                num_pixels_changed += 1;
+                max_diff = max_diff.max(this_max);
+                diff_image.set_pixel(x, y, RED_PIXEL);
+            }
+        }
+    }
+
+    (num_pixels_changed, max_diff)
+}
+
+/// Squared perceptual color distance threshold for a given `[0, 1]` threshold,
+/// per Yee's perceptual metric (as used by `pixelmatch`).
+fn max_delta_for_threshold(threshold: f64) -> f64 {
+    35215.0 * threshold * threshold
+}
+
+/// Unpremultiplies `pixel` and blends the resulting color over a solid white
+/// background.
+fn blend(pixel: Pixel) -> (f64, f64, f64) {
+    let pixel = pixel.unpremultiply();
+    let alpha = f64::from(pixel.a) / 255.0;
+    let background = 255.0;
+
+    let blend_channel = |c: u8| f64::from(c) * alpha + background * (1.0 - alpha);
+
+    (
+        blend_channel(pixel.r),
+        blend_channel(pixel.g),
+        blend_channel(pixel.b),
+    )
+}
+
+struct Yiq {
+    y: f64,
+    i: f64,
+    q: f64,
+}
+
+fn rgb_to_yiq(r: f64, g: f64, b: f64) -> Yiq {
+    Yiq {
+        y: 0.298_895_31 * r + 0.586_622_47 * g + 0.114_482_23 * b,
+        i: 0.595_977_99 * r - 0.274_176_10 * g - 0.321_801_89 * b,
+        q: 0.211_470_17 * r - 0.522_617_11 * g + 0.311_146_94 * b,
+    }
+}
+
+/// Squared perceptual color distance between two pixels, after blending both
+/// over a solid background.
+fn color_delta(a: Pixel, b: Pixel) -> f64 {
+    let (ar, ag, ab) = blend(a);
+    let (br, bg, bb) = blend(b);
+
+    let ay = rgb_to_yiq(ar, ag, ab);
+    let by = rgb_to_yiq(br, bg, bb);
+
+    let dy = ay.y - by.y;
+    let di = ay.i - by.i;
+    let dq = ay.q - by.q;
+
+    0.5053 * dy * dy + 0.299 * di * di + 0.1957 * dq * dq
+}
+
+/// Brightness used for the anti-aliasing neighborhood test (just Y from YIQ).
+fn brightness(pixel: Pixel) -> f64 {
+    let (r, g, b) = blend(pixel);
+    rgb_to_yiq(r, g, b).y
+}
+
+/// Checks whether the pixel at `(x, y)` in `surf` looks like it belongs to an
+/// anti-aliased edge: among its 3x3 neighborhood, at most two neighbors have
+/// exactly the same brightness as the center, and there is both a much darker
+/// and a much brighter neighbor.
+///
+/// On success, returns the coordinates of the darkest and brightest
+/// neighbors (in that order), so the caller can check whether *those*
+/// locations also look like anti-aliasing in another surface.
+fn looks_like_anti_aliasing(
+    surf: &SharedImageSurface,
+    x: u32,
+    y: u32,
+) -> Option<((u32, u32), (u32, u32))> {
+    let width = surf.get_width() as u32;
+    let height = surf.get_height() as u32;
+
+    let center = brightness(surf.get_pixel(x, y));
+
+    let mut min_delta = 0.0_f64;
+    let mut max_delta = 0.0_f64;
+    let mut min_pos = None;
+    let mut max_pos = None;
+    let mut equal_neighbors = 0;
+
+    for dy in -1i64..=1 {
+        for dx in -1i64..=1 {
+            if dx == 0 && dy == 0 {
+                continue;
+            }
+
+            let nx = x as i64 + dx;
+            let ny = y as i64 + dy;
+            if nx < 0 || ny < 0 || nx >= width as i64 || ny >= height as i64 {
+                continue;
+            }
+
+            let nx = nx as u32;
+            let ny = ny as u32;
+
+            let neighbor = brightness(surf.get_pixel(nx, ny));
+            let delta = neighbor - center;
+
+            if delta == 0.0 {
+                equal_neighbors += 1;
+                continue;
+            }
+
+            if delta < min_delta {
+                min_delta = delta;
+                min_pos = Some((nx, ny));
+            }
+
+            if delta > max_delta {
+                max_delta = delta;
+                max_pos = Some((nx, ny));
+            }
+        }
+    }
+
+    if equal_neighbors > 2 {
+        return None;
+    }
+
+    match (min_pos, max_pos) {
+        (Some(min_pos), Some(max_pos)) => Some((min_pos, max_pos)),
+        _ => None,
+    }
+}
+
+/// Checks whether `(x, y)` is an anti-aliased pixel: `surf` must look like
+/// anti-aliasing at `(x, y)`, *and* the location of its darkest or brightest
+/// neighbor (whichever one triggered the classification) must itself look
+/// like anti-aliasing in `other`.
+fn is_anti_aliased(surf: &SharedImageSurface, other: &SharedImageSurface, x: u32, y: u32) -> bool {
+    let (min_pos, max_pos) = match looks_like_anti_aliasing(surf, x, y) {
+        Some(positions) => positions,
+        None => return false,
+    };
+
+    let is_aa_in_other = |(nx, ny): (u32, u32)| looks_like_anti_aliasing(other, nx, ny).is_some();
+
+    is_aa_in_other(min_pos) || is_aa_in_other(max_pos)
+}
+
+fn anti_aliasing_diff(
+    surf_a: &SharedImageSurface,
+    surf_b: &SharedImageSurface,
+    diff_image: &mut DiffImage,
+    threshold: f64,
+) -> (usize, u8) {
+    let width = surf_a.get_width() as u32;
+    let height = surf_a.get_height() as u32;
+
+    let max_delta = max_delta_for_threshold(threshold);
+
+    let mut num_pixels_changed = 0;
+    let mut max_diff = 0u8;
+
+    for y in 0..height {
+        for x in 0..width {
+            let pixel_a = surf_a.get_pixel(x, y);
+            let pixel_b = surf_b.get_pixel(x, y);
+
+            if color_delta(pixel_a, pixel_b) <= max_delta {
+                continue;
+            }
+
+            if is_anti_aliased(surf_a, surf_b, x, y) {
+                diff_image.set_pixel(x, y, YELLOW_PIXEL);
+                continue;
+            }
+
+            num_pixels_changed += 1;
+
+            let naive_max = [
+                diff_channel(pixel_a.r, pixel_b.r),
+                diff_channel(pixel_a.g, pixel_b.g),
+                diff_channel(pixel_a.b, pixel_b.b),
+                diff_channel(pixel_a.a, pixel_b.a),
+            ]
+            .iter()
+            .copied()
+            .max()
+            .unwrap();
+
+            max_diff = max_diff.max(naive_max);
+            diff_image.set_pixel(x, y, RED_PIXEL);
+        }
+    }
+
+    (num_pixels_changed, max_diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray(value: u8) -> Pixel {
+        Pixel {
+            r: value,
+            g: value,
+            b: value,
+            a: 255,
+        }
+    }
+
+    /// Builds a `width`x`height` opaque surface out of `pixels`, in row-major order.
+    fn surface_from_pixels(width: i32, height: i32, pixels: &[Pixel]) -> SharedImageSurface {
+        assert_eq!(pixels.len(), (width * height) as usize);
+
+        let mut surface = ImageSurface::create(Format::ARgb32, width, height).unwrap();
+        let stride = surface.get_stride();
+
+        {
+            let mut data = surface.get_data().unwrap();
+            for (i, pixel) in pixels.iter().enumerate() {
+                let x = i as i32 % width;
+                let y = i as i32 / width;
+                let offset = (y * stride + x * 4) as usize;
+                data[offset] = pixel.b;
+                data[offset + 1] = pixel.g;
+                data[offset + 2] = pixel.r;
+                data[offset + 3] = pixel.a;
+            }
+        }
+
+        SharedImageSurface::wrap(surface, SurfaceType::SRgb).unwrap()
+    }
+
+    /// A horizontal brightness ramp, repeated on every row: a smooth
+    /// transition from black to white, like a soft anti-aliased edge.
+    fn ramp_surface(width: i32, height: i32) -> SharedImageSurface {
+        let mut pixels = Vec::new();
+        for _ in 0..height {
+            for x in 0..width {
+                let value = (x * 255 / (width - 1)) as u8;
+                pixels.push(gray(value));
+            }
+        }
+        surface_from_pixels(width, height, &pixels)
+    }
+
+    fn flat_surface(width: i32, height: i32, value: u8) -> SharedImageSurface {
+        surface_from_pixels(width, height, &vec![gray(value); (width * height) as usize])
+    }
+
+    #[test]
+    fn color_delta_is_zero_for_identical_pixels() {
+        let p = gray(100);
+        assert_eq!(color_delta(p, p), 0.0);
+    }
+
+    #[test]
+    fn color_delta_grows_with_color_distance() {
+        let black = gray(0);
+        let white = gray(255);
+        let light_gray = gray(200);
+
+        assert!(color_delta(black, white) > color_delta(black, light_gray));
+    }
+
+    #[test]
+    fn max_delta_for_threshold_scales_with_threshold_squared() {
+        assert_eq!(max_delta_for_threshold(0.0), 0.0);
+        assert!((max_delta_for_threshold(1.0) - 35215.0).abs() < 1e-9);
+        assert!(max_delta_for_threshold(0.5) < max_delta_for_threshold(1.0));
+    }
+
+    #[test]
+    fn flat_surface_has_no_anti_aliased_pixels() {
+        let surf = flat_surface(3, 3, 128);
+
+        assert!(looks_like_anti_aliasing(&surf, 1, 1).is_none());
+    }
+
+    #[test]
+    fn ramp_pixel_looks_like_anti_aliasing() {
+        let surf = ramp_surface(5, 3);
+
+        assert!(looks_like_anti_aliasing(&surf, 2, 1).is_some());
+    }
+
+    #[test]
+    fn matching_ramps_are_recognized_as_anti_aliasing_in_each_other() {
+        let surf_a = ramp_surface(5, 3);
+        let surf_b = ramp_surface(5, 3);
+
+        assert!(is_anti_aliased(&surf_a, &surf_b, 2, 1));
+    }
+
+    #[test]
+    fn a_ramp_against_a_flat_reference_is_not_anti_aliasing() {
+        // The pixel at (2, 1) looks like it could be an anti-aliased edge in
+        // isolation, but the reference image is flat there: a real
+        // difference, not AA noise that should be excluded from the count.
+        let surf_a = ramp_surface(5, 3);
+        let surf_b = flat_surface(5, 3, 255);
+
+        assert!(!is_anti_aliased(&surf_a, &surf_b, 2, 1));
+    }
+}
@@ -0,0 +1,4 @@
+//! Utilities for working with Cairo image surfaces.
+
+pub mod compare_surfaces;
+pub mod shared_surface;
@@ -0,0 +1,137 @@
+//! Read-only, shareable wrapper around a Cairo image surface.
+
+use std::fmt;
+
+/// The color space in which a surface's pixel values are to be interpreted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SurfaceType {
+    /// Non-linear sRGB values.
+    SRgb,
+    /// Linear RGB values.
+    LinearRgb,
+    /// Alpha channel only; the other channels should be ignored.
+    AlphaOnly,
+}
+
+/// A single pixel's components, as stored in a Cairo `ARgb32` surface (premultiplied).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Pixel {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Pixel {
+    pub fn new(r: u8, g: u8, b: u8, a: u8) -> Self {
+        Self { r, g, b, a }
+    }
+
+    /// Returns this pixel's components with the color channels undone from
+    /// premultiplication by `a`.
+    pub fn unpremultiply(self) -> Self {
+        if self.a == 0 {
+            self
+        } else {
+            let a = f64::from(self.a);
+            let unpremultiply = |c: u8| (f64::from(c) * 255.0 / a).round().min(255.0) as u8;
+
+            Pixel {
+                r: unpremultiply(self.r),
+                g: unpremultiply(self.g),
+                b: unpremultiply(self.b),
+                a: self.a,
+            }
+        }
+    }
+}
+
+/// A Cairo `ImageSurface` of format `ARgb32`, wrapped so it can be read from
+/// multiple places without each caller having to deal with Cairo's mutable
+/// borrowing rules.
+#[derive(Clone)]
+pub struct SharedImageSurface {
+    surface: cairo::ImageSurface,
+    data: std::rc::Rc<Vec<u8>>,
+
+    width: i32,
+    height: i32,
+    stride: i32,
+
+    surface_type: SurfaceType,
+}
+
+impl fmt::Debug for SharedImageSurface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedImageSurface")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("surface_type", &self.surface_type)
+            .finish()
+    }
+}
+
+impl SharedImageSurface {
+    /// Wraps a Cairo image surface, copying its pixel data so it can be read
+    /// from safely regardless of what happens to `surface` afterwards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `surface` is not in `ARgb32` format.
+    pub fn wrap(surface: cairo::ImageSurface, surface_type: SurfaceType) -> Result<Self, cairo::Status> {
+        assert_eq!(surface.get_format(), cairo::Format::ARgb32);
+
+        let width = surface.get_width();
+        let height = surface.get_height();
+        let stride = surface.get_stride();
+
+        let data = {
+            let data_ref = surface.get_data().map_err(|_| cairo::Status::SurfaceFinished)?;
+            data_ref.to_vec()
+        };
+
+        Ok(Self {
+            surface,
+            data: std::rc::Rc::new(data),
+            width,
+            height,
+            stride,
+            surface_type,
+        })
+    }
+
+    pub fn get_width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn get_height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn surface_type(&self) -> SurfaceType {
+        self.surface_type
+    }
+
+    /// Returns the pixel at `(x, y)`, in premultiplied `ARgb32` order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `(x, y)` is out of bounds.
+    pub fn get_pixel(&self, x: u32, y: u32) -> Pixel {
+        assert!(x < self.width as u32);
+        assert!(y < self.height as u32);
+
+        let offset = y as isize * self.stride as isize + x as isize * 4;
+        let b = self.data[offset as usize];
+        let g = self.data[offset as usize + 1];
+        let r = self.data[offset as usize + 2];
+        let a = self.data[offset as usize + 3];
+
+        Pixel::new(r, g, b, a)
+    }
+
+    /// Consumes `self` and returns the underlying Cairo surface.
+    pub fn into_image_surface(self) -> Result<cairo::ImageSurface, cairo::Status> {
+        Ok(self.surface)
+    }
+}
@@ -3,15 +3,17 @@
 //! This module has utility functions that are used in the test suite
 //! to compare rendered surfaces to reference images.
 
-use crate::surface_utils::compare_surfaces::{compare_surfaces, BufferDiff, Diff};
+use crate::surface_utils::compare_surfaces::{
+    compare_surfaces, compare_surfaces_with_mode, BufferDiff, CompareMode, Diff,
+};
 use crate::surface_utils::shared_surface::{SharedImageSurface, SurfaceType};
 
 use std::convert::TryFrom;
 use std::env;
 use std::fs::{self, File};
-use std::io::BufReader;
+use std::io::{BufReader, Read, Write};
 use std::path::PathBuf;
-use std::sync::Once;
+use std::sync::{Mutex, Once};
 
 fn tolerable_difference() -> u8 {
     static mut TOLERANCE: u8 = 2;
@@ -30,6 +32,38 @@ fn tolerable_difference() -> u8 {
     unsafe { TOLERANCE }
 }
 
+fn max_changed_pixels() -> Option<usize> {
+    static mut MAX_CHANGED_PIXELS: Option<usize> = None;
+
+    static ONCE: Once = Once::new();
+    ONCE.call_once(|| unsafe {
+        if let Ok(str) = env::var("RSVG_TEST_MAX_CHANGED_PIXELS") {
+            let value: usize = str
+                .parse()
+                .expect("Can not parse RSVG_TEST_MAX_CHANGED_PIXELS as a number");
+            MAX_CHANGED_PIXELS = Some(value);
+        }
+    });
+
+    unsafe { MAX_CHANGED_PIXELS }
+}
+
+fn max_changed_fraction() -> Option<f64> {
+    static mut MAX_CHANGED_FRACTION: Option<f64> = None;
+
+    static ONCE: Once = Once::new();
+    ONCE.call_once(|| unsafe {
+        if let Ok(str) = env::var("RSVG_TEST_MAX_CHANGED_FRACTION") {
+            let value: f64 = str
+                .parse()
+                .expect("Can not parse RSVG_TEST_MAX_CHANGED_FRACTION as a number");
+            MAX_CHANGED_FRACTION = Some(value);
+        }
+    });
+
+    unsafe { MAX_CHANGED_FRACTION }
+}
+
 trait Deviation {
     fn distinguishable(&self) -> bool;
     fn inacceptable(&self) -> bool;
@@ -41,7 +75,98 @@ impl Deviation for Diff {
     }
 
     fn inacceptable(&self) -> bool {
-        self.max_diff > tolerable_difference()
+        diff_inacceptable(self.max_diff, tolerable_difference(), self.changed_pixel_budget_exceeded())
+    }
+}
+
+impl Diff {
+    /// Whether `num_pixels_changed` blows the budget configured via
+    /// `RSVG_TEST_MAX_CHANGED_PIXELS`/`RSVG_TEST_MAX_CHANGED_FRACTION`.
+    ///
+    /// With neither variable set, there is no budget to speak of, so this
+    /// always returns `true` and `inacceptable()` behaves as if only
+    /// `max_diff` mattered.
+    fn changed_pixel_budget_exceeded(&self) -> bool {
+        let total_pixels = self.surface.get_width() as usize * self.surface.get_height() as usize;
+
+        budget_exceeded(
+            self.num_pixels_changed,
+            total_pixels,
+            max_changed_pixels(),
+            max_changed_fraction(),
+        )
+    }
+}
+
+/// Pure core of `Diff::changed_pixel_budget_exceeded`, split out so it can be
+/// unit tested without going through the env-var-backed, `Once`-cached getters.
+fn budget_exceeded(
+    num_pixels_changed: usize,
+    total_pixels: usize,
+    pixels_budget: Option<usize>,
+    fraction_budget: Option<f64>,
+) -> bool {
+    if pixels_budget.is_none() && fraction_budget.is_none() {
+        return true;
+    }
+
+    let exceeds_pixels = pixels_budget.map_or(false, |max| num_pixels_changed > max);
+
+    let exceeds_fraction = fraction_budget.map_or(false, |max| {
+        total_pixels > 0 && (num_pixels_changed as f64 / total_pixels as f64) > max
+    });
+
+    exceeds_pixels || exceeds_fraction
+}
+
+/// Pure core of `Diff::inacceptable`, split out for the same reason as
+/// `budget_exceeded`.
+fn diff_inacceptable(max_diff: u8, tolerable: u8, budget_exceeded: bool) -> bool {
+    max_diff > tolerable && budget_exceeded
+}
+
+#[cfg(test)]
+mod budget_tests {
+    use super::*;
+
+    #[test]
+    fn no_budget_set_always_exceeds() {
+        assert!(budget_exceeded(0, 100, None, None));
+        assert!(budget_exceeded(100, 100, None, None));
+    }
+
+    #[test]
+    fn exceeds_pixel_count_but_not_fraction() {
+        assert!(budget_exceeded(10, 1000, Some(5), Some(0.5)));
+    }
+
+    #[test]
+    fn exceeds_fraction_but_not_pixel_count() {
+        assert!(budget_exceeded(10, 20, Some(100), Some(0.25)));
+    }
+
+    #[test]
+    fn within_both_budgets_does_not_exceed() {
+        assert!(!budget_exceeded(5, 1000, Some(10), Some(0.5)));
+    }
+
+    #[test]
+    fn pixel_budget_boundary_is_exclusive() {
+        assert!(!budget_exceeded(5, 1000, Some(5), None));
+        assert!(budget_exceeded(6, 1000, Some(5), None));
+    }
+
+    #[test]
+    fn fraction_budget_boundary_is_exclusive() {
+        assert!(!budget_exceeded(5, 20, None, Some(0.25)));
+        assert!(budget_exceeded(6, 20, None, Some(0.25)));
+    }
+
+    #[test]
+    fn inacceptable_requires_both_max_diff_and_budget_exceeded() {
+        assert!(!diff_inacceptable(10, 2, false));
+        assert!(!diff_inacceptable(1, 2, true));
+        assert!(diff_inacceptable(10, 2, true));
     }
 }
 
@@ -70,10 +195,12 @@ pub fn output_dir() -> PathBuf {
 // FIXME: proper errors?
 fn load_png_as_argb(path: &PathBuf) -> Result<cairo::ImageSurface, ()> {
     let file = File::open(path).map_err(|_| ())?;
+    load_png_as_argb_from_read(&mut BufReader::new(file))
+}
 
-    let mut reference_file = BufReader::new(file);
-
-    let png = cairo::ImageSurface::create_from_png(&mut reference_file).map_err(|_| ())?;
+// FIXME: proper errors?
+fn load_png_as_argb_from_read<R: Read>(stream: &mut R) -> Result<cairo::ImageSurface, ()> {
+    let png = cairo::ImageSurface::create_from_png(stream).map_err(|_| ())?;
     let argb =
         cairo::ImageSurface::create(cairo::Format::ARgb32, png.get_width(), png.get_height())
             .map_err(|_| ())?;
@@ -88,6 +215,27 @@ fn load_png_as_argb(path: &PathBuf) -> Result<cairo::ImageSurface, ()> {
     Ok(argb)
 }
 
+/// Decodes `reference` and `candidate` as PNG images and compares them.
+///
+/// Unlike `compare_to_file`/`compare_to_surface`, this does not panic on a
+/// difference; it just returns the `BufferDiff` so callers (e.g. command-line
+/// integration tests asserting on PNG bytes they produced) can build their
+/// own predicates on top of it, printing it via its `Display` impl if they
+/// want a human-readable summary.
+///
+/// # Panics
+///
+/// Panics if either `reference` or `candidate` cannot be decoded as a PNG.
+pub fn compare_png_buffers<R1: Read, R2: Read>(mut reference: R1, mut candidate: R2) -> BufferDiff {
+    let reference_surf = load_png_as_argb_from_read(&mut reference).unwrap();
+    let reference_surf = SharedImageSurface::wrap(reference_surf, SurfaceType::SRgb).unwrap();
+
+    let candidate_surf = load_png_as_argb_from_read(&mut candidate).unwrap();
+    let candidate_surf = SharedImageSurface::wrap(candidate_surf, SurfaceType::SRgb).unwrap();
+
+    compare_surfaces(&candidate_surf, &reference_surf).unwrap()
+}
+
 /// Compares `output_surf` to the reference image from `reference_path`.
 ///
 /// Loads the image stored at `reference_path` and uses `compare_to_surface` to
@@ -97,15 +245,27 @@ fn load_png_as_argb(path: &PathBuf) -> Result<cairo::ImageSurface, ()> {
 ///
 /// See `compare_to_surface` for information; this function compares the images and panics in the
 /// same way as that function upon encountering differences.
-pub fn compare_to_file(
+pub fn compare_to_file(output_surf: &SharedImageSurface, output_base_name: &str, reference_path: &PathBuf) {
+    compare_to_file_with_mode(output_surf, output_base_name, reference_path, CompareMode::default());
+}
+
+/// Like `compare_to_file`, but lets the caller pick the comparison mode.
+///
+/// # Panics
+///
+/// See `compare_to_surface` for information; this function compares the images and panics in the
+/// same way as that function upon encountering differences.
+pub fn compare_to_file_with_mode(
     output_surf: &SharedImageSurface,
     output_base_name: &str,
     reference_path: &PathBuf,
+    mode: CompareMode,
 ) {
     let png = load_png_as_argb(reference_path).unwrap();
     let reference_surf = SharedImageSurface::wrap(png, SurfaceType::SRgb).unwrap();
 
-    compare_to_surface(output_surf, &reference_surf, output_base_name);
+    let diff = compare_surfaces_with_mode(output_surf, &reference_surf, mode).unwrap();
+    evaluate_diff(&diff, output_surf, output_base_name, Some(reference_path));
 }
 
 /// Compares two surfaces and panics if they are too different.
@@ -123,23 +283,41 @@ pub fn compare_to_surface(
     reference_surf: &SharedImageSurface,
     output_base_name: &str,
 ) {
-    let diff = compare_surfaces(output_surf, reference_surf).unwrap();
-    evaluate_diff(&diff, output_surf, output_base_name);
+    compare_to_surface_with_mode(output_surf, reference_surf, output_base_name, CompareMode::default());
 }
 
-fn evaluate_diff(diff: &BufferDiff, output_surf: &SharedImageSurface, output_base_name: &str) {
+/// Like `compare_to_surface`, but lets the caller pick the comparison mode.
+///
+/// # Panics
+///
+/// Will panic if the surfaces are too different to be acceptable.
+pub fn compare_to_surface_with_mode(
+    output_surf: &SharedImageSurface,
+    reference_surf: &SharedImageSurface,
+    output_base_name: &str,
+    mode: CompareMode,
+) {
+    let diff = compare_surfaces_with_mode(output_surf, reference_surf, mode).unwrap();
+    evaluate_diff(&diff, output_surf, output_base_name, None);
+}
+
+fn evaluate_diff(
+    diff: &BufferDiff,
+    output_surf: &SharedImageSurface,
+    output_base_name: &str,
+    reference_path: Option<&PathBuf>,
+) {
     match diff {
         BufferDiff::DifferentSizes => unreachable!("surfaces should be of the same size"),
 
         BufferDiff::Diff(diff) => {
             if diff.distinguishable() {
-                println!(
-                    "{}: {} pixels changed with maximum difference of {}",
-                    output_base_name, diff.num_pixels_changed, diff.max_diff,
-                );
+                println!("{}: {}", output_base_name, diff);
+
+                let out_path = write_to_file(output_surf, output_base_name, "out");
+                let diff_path = write_to_file(&diff.surface, output_base_name, "diff");
 
-                write_to_file(output_surf, output_base_name, "out");
-                write_to_file(&diff.surface, output_base_name, "diff");
+                write_report(output_base_name, diff, reference_path, &out_path, &diff_path);
 
                 if diff.inacceptable() {
                     panic!("surfaces are too different");
@@ -149,14 +327,145 @@ fn evaluate_diff(diff: &BufferDiff, output_surf: &SharedImageSurface, output_bas
     }
 }
 
-fn write_to_file(input: &SharedImageSurface, output_base_name: &str, suffix: &str) {
+fn write_to_file(input: &SharedImageSurface, output_base_name: &str, suffix: &str) -> PathBuf {
     let path = output_dir().join(&format!("{}-{}.png", output_base_name, suffix));
     println!("{}: {}", suffix, path.to_string_lossy());
-    let mut output_file = File::create(path).unwrap();
+    let mut output_file = File::create(&path).unwrap();
     input
         .clone()
         .into_image_surface()
         .unwrap()
         .write_to_png(&mut output_file)
         .unwrap();
+    path
+}
+
+/// Writes a machine-readable JSON record of `diff` under `RSVG_TEST_REPORT_DIR`,
+/// if that variable is set: a per-test `{output_base_name}-diff.json` file in
+/// that directory, and an entry appended to the consolidated `index.jsonl`
+/// there for the whole test run.  The `-out.png`/`-diff.png` files stay in
+/// `output_dir()`; the record holds their paths.
+///
+/// Each line of the index is a standalone JSON object, so a whole test run
+/// can be parsed by reading the file line by line without buffering the
+/// entire run's output in memory.
+fn write_report(
+    output_base_name: &str,
+    diff: &Diff,
+    reference_path: Option<&PathBuf>,
+    out_path: &PathBuf,
+    diff_path: &PathBuf,
+) {
+    let report_dir = match env::var_os("RSVG_TEST_REPORT_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => return,
+    };
+
+    fs::create_dir_all(&report_dir).expect("could not create report directory");
+
+    let width = diff.surface.get_width();
+    let height = diff.surface.get_height();
+    let total_pixels = width as usize * height as usize;
+
+    let reference_path = reference_path
+        .map(|p| format!("{:?}", p.to_string_lossy()))
+        .unwrap_or_else(|| "null".to_string());
+
+    let entry = format!(
+        "{{\"test\":{:?},\"num_pixels_changed\":{},\"max_diff\":{},\"total_pixels\":{},\
+         \"width\":{},\"height\":{},\"tolerance\":{},\"out_path\":{:?},\"diff_path\":{:?},\
+         \"reference_path\":{}}}",
+        output_base_name,
+        diff.num_pixels_changed,
+        diff.max_diff,
+        total_pixels,
+        width,
+        height,
+        tolerable_difference(),
+        out_path.to_string_lossy(),
+        diff_path.to_string_lossy(),
+        reference_path,
+    );
+
+    let report_path = report_dir.join(&format!("{}-diff.json", output_base_name));
+    fs::write(&report_path, &entry).expect("could not write per-test report file");
+
+    // Build the whole line (including the trailing newline) up front and issue
+    // a single `write_all`, guarded by a lock: `O_APPEND` only makes a single
+    // `write()` atomic, and separate writes for the body and the newline (as
+    // `writeln!` would do) could interleave with another thread's and corrupt
+    // the index, since `cargo test` runs tests on multiple threads by default.
+    let line = format!("{}\n", entry);
+    let _guard = report_index_lock().lock().unwrap();
+
+    let mut index_file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(report_dir.join("index.jsonl"))
+        .expect("could not open test report index file");
+
+    index_file
+        .write_all(line.as_bytes())
+        .expect("could not write test report entry");
+}
+
+/// Serializes access to `index.jsonl` across the threads `cargo test` runs
+/// tests on within this process.
+fn report_index_lock() -> &'static Mutex<()> {
+    static mut LOCK: Option<Mutex<()>> = None;
+
+    static ONCE: Once = Once::new();
+    ONCE.call_once(|| unsafe {
+        LOCK = Some(Mutex::new(()));
+    });
+
+    unsafe { LOCK.as_ref().unwrap() }
+}
+
+#[cfg(test)]
+mod write_report_tests {
+    use super::*;
+
+    fn stub_diff(width: i32, height: i32) -> Diff {
+        let surface = cairo::ImageSurface::create(cairo::Format::ARgb32, width, height).unwrap();
+        let surface = SharedImageSurface::wrap(surface, SurfaceType::SRgb).unwrap();
+
+        Diff {
+            surface,
+            num_pixels_changed: 3,
+            max_diff: 42,
+        }
+    }
+
+    #[test]
+    fn writes_per_test_file_and_accumulates_index_entries() {
+        // write_report reads RSVG_TEST_REPORT_DIR itself (no Once-caching),
+        // so this test drives it directly rather than through evaluate_diff.
+        let report_dir = output_dir().join("write_report_tests");
+        fs::remove_dir_all(&report_dir).ok();
+        env::set_var("RSVG_TEST_REPORT_DIR", &report_dir);
+
+        let diff = stub_diff(4, 4);
+        let out_path = PathBuf::from("foo-out.png");
+        let diff_path = PathBuf::from("foo-diff.png");
+
+        write_report("foo", &diff, None, &out_path, &diff_path);
+        write_report("foo", &diff, None, &out_path, &diff_path);
+
+        env::remove_var("RSVG_TEST_REPORT_DIR");
+
+        let per_test_contents = fs::read_to_string(report_dir.join("foo-diff.json")).unwrap();
+        assert!(per_test_contents.contains("\"num_pixels_changed\":3"));
+        assert!(per_test_contents.contains("\"max_diff\":42"));
+
+        let index_contents = fs::read_to_string(report_dir.join("index.jsonl")).unwrap();
+        let lines: Vec<&str> = index_contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for line in lines {
+            assert!(line.starts_with('{') && line.ends_with('}'));
+            assert!(line.contains("\"test\":\"foo\""));
+        }
+
+        fs::remove_dir_all(&report_dir).ok();
+    }
 }